@@ -1,30 +1,158 @@
 use std::vec;
+use std::collections::HashMap;
 use std::time::Duration;
 use futures::{Future, Stream, Poll, Async};
 use futures_after::{After, AfterStream};
-use tokio_timer::{Timer, Interval};
+use tokio_timer::{Timer, Interval, Timeout, TimeoutError};
 use web3::{self, api, Transport};
 use web3::api::{Namespace, FilterStream, CreateFilter};
-use web3::types::{Log, Filter, H256, Block, BlockId, BlockNumber, U256, FilterBuilder, TransactionRequest};
+use web3::types::{Log, Filter, H256, Block, BlockId, BlockNumber, U256, Address, FilterBuilder, TransactionRequest};
 use web3::helpers::CallResult;
 use error::{Error, ErrorKind};
 
 pub use web3::confirm::send_transaction_with_confirmation;
 
-pub fn logs<T: Transport>(transport: T, filter: &Filter) -> CallResult<Vec<Log>, T::Out> {
-	api::Eth::new(transport).logs(filter)
+/// a `CallResult` labeled with its RPC method, for per-call tracing.
+pub struct ApiCall<T, F> {
+	future: CallResult<T, F>,
+	message: &'static str,
 }
 
-pub fn block<T: Transport>(transport: T, id: BlockId) -> CallResult<Block<H256>, T::Out> {
-	api::Eth::new(transport).block(id)
+impl<T, F> ApiCall<T, F> {
+	fn new(future: CallResult<T, F>, message: &'static str) -> Self {
+		ApiCall { future, message }
+	}
+}
+
+impl<T, F> Future for ApiCall<T, F> where CallResult<T, F>: Future<Item = T, Error = web3::Error> {
+	type Item = T;
+	type Error = Error;
+
+	fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+		trace!(target: "bridge", "{}", self.message);
+		self.future.poll().map_err(|e| ErrorKind::Web3(e).into())
+	}
+}
+
+pub fn logs<T: Transport>(transport: T, filter: &Filter) -> ApiCall<Vec<Log>, T::Out> {
+	ApiCall::new(api::Eth::new(transport).logs(filter), "eth_getLogs")
+}
+
+pub fn block<T: Transport>(transport: T, id: BlockId) -> ApiCall<Block<H256>, T::Out> {
+	ApiCall::new(api::Eth::new(transport).block(id), "eth_getBlockByNumber")
+}
+
+pub fn block_number<T: Transport>(transport: T) -> ApiCall<U256, T::Out> {
+	ApiCall::new(api::Eth::new(transport).block_number(), "eth_blockNumber")
+}
+
+pub fn send_transaction<T: Transport>(transport: T, tx: TransactionRequest) -> ApiCall<H256, T::Out> {
+	ApiCall::new(api::Eth::new(transport).send_transaction(tx), "eth_sendTransaction")
+}
+
+/// transaction count for `address` at `block`; pass `BlockNumber::Pending`, not
+/// `latest`, or unmined transactions of ours go uncounted and nonces collide.
+pub fn eth_get_transaction_count<T: Transport>(transport: T, address: Address, block: BlockNumber) -> ApiCall<U256, T::Out> {
+	ApiCall::new(api::Eth::new(transport).transaction_count(address, Some(block)), "eth_getTransactionCount")
 }
 
-pub fn block_number<T: Transport>(transport: T) -> CallResult<U256, T::Out> {
-	api::Eth::new(transport).block_number()
+/// caches the next nonce per signing address, incremented locally between submissions.
+pub struct NonceManager {
+	next_nonce: HashMap<Address, U256>,
+}
+
+impl Default for NonceManager {
+	fn default() -> Self {
+		NonceManager::new()
+	}
+}
+
+impl NonceManager {
+	pub fn new() -> Self {
+		NonceManager { next_nonce: HashMap::new() }
+	}
+
+	/// `None` means `address` hasn't been primed yet: call `resync` first.
+	pub fn next(&mut self, address: Address) -> Option<U256> {
+		let nonce = *self.next_nonce.get(&address)?;
+		self.next_nonce.insert(address, nonce + U256::from(1));
+		Some(nonce)
+	}
+
+	pub fn resync(&mut self, address: Address, pending: U256) {
+		self.next_nonce.insert(address, pending);
+	}
 }
 
-pub fn send_transaction<T: Transport>(transport: T, tx: TransactionRequest) -> CallResult<H256, T::Out> {
-	api::Eth::new(transport).send_transaction(tx)
+fn with_nonce(mut tx: TransactionRequest, nonce: U256) -> TransactionRequest {
+	tx.nonce = Some(nonce);
+	tx
+}
+
+/// submits `tx` with a nonce from `nonce_manager` rather than a node-assigned one.
+/// primes `nonce_manager` from `eth_get_transaction_count` at `pending` if `address`
+/// hasn't been seen before, and resyncs the same way if the send itself fails.
+pub fn send_transaction_with_nonce<'a, T: Transport + Clone>(transport: T, nonce_manager: &'a mut NonceManager, tx: TransactionRequest) -> SendWithNonce<'a, T> {
+	let from = tx.from;
+	let state = match nonce_manager.next(from) {
+		Some(nonce) => SendWithNonceState::Send(send_transaction(transport.clone(), with_nonce(tx.clone(), nonce))),
+		None => SendWithNonceState::Prime(eth_get_transaction_count(transport.clone(), from, BlockNumber::Pending)),
+	};
+	SendWithNonce { transport, nonce_manager, from, tx, state }
+}
+
+enum SendWithNonceState<T: Transport> {
+	Prime(ApiCall<U256, T::Out>),
+	Send(ApiCall<H256, T::Out>),
+	Resync {
+		future: ApiCall<U256, T::Out>,
+		err: Option<Error>,
+	},
+}
+
+pub struct SendWithNonce<'a, T: Transport> {
+	transport: T,
+	nonce_manager: &'a mut NonceManager,
+	from: Address,
+	tx: TransactionRequest,
+	state: SendWithNonceState<T>,
+}
+
+impl<'a, T: Transport + Clone> Future for SendWithNonce<'a, T> {
+	type Item = H256;
+	type Error = Error;
+
+	fn poll(&mut self) -> Poll<H256, Error> {
+		loop {
+			let next_state = match self.state {
+				SendWithNonceState::Prime(ref mut future) => {
+					let pending = try_ready!(future.poll());
+					self.nonce_manager.resync(self.from, pending);
+					let nonce = self.nonce_manager.next(self.from).expect("just resynced");
+					SendWithNonceState::Send(send_transaction(self.transport.clone(), with_nonce(self.tx.clone(), nonce)))
+				},
+				SendWithNonceState::Send(ref mut future) => match future.poll() {
+					Ok(async_hash) => return Ok(async_hash),
+					Err(err) => SendWithNonceState::Resync {
+						future: eth_get_transaction_count(self.transport.clone(), self.from, BlockNumber::Pending),
+						err: Some(err),
+					},
+				},
+				// the resync call is best-effort: if it fails too, the caller still needs
+				// the original send error, not "resync RPC failed", to act on.
+				SendWithNonceState::Resync { ref mut future, ref mut err } => match future.poll() {
+					Ok(Async::Ready(pending)) => {
+						self.nonce_manager.resync(self.from, pending);
+						return Err(err.take().expect("set when entering Resync"));
+					},
+					Ok(Async::NotReady) => return Ok(Async::NotReady),
+					Err(_) => return Err(err.take().expect("set when entering Resync")),
+				},
+			};
+
+			self.state = next_state;
+		}
+	}
 }
 
 pub struct LogStreamInit {
@@ -32,32 +160,113 @@ pub struct LogStreamInit {
 	pub filter: FilterBuilder,
 	pub poll_interval: Duration,
 	pub confirmations: usize,
+	/// max blocks per `eth_getLogs` call. `0` means unbounded.
+	pub block_page_size: u64,
+	/// blocks to rewind `after` by when a re-org is detected.
+	pub reorg_rewind: u64,
+	/// max time to wait for a single RPC call.
+	pub request_timeout: Duration,
 }
 
 pub struct LogStreamItem {
 	pub from: u64,
 	pub to: u64,
 	pub logs: Vec<Log>,
+	/// `Some(block)` if a re-org rewound the stream to `block`, so this item
+	/// re-scans from `block + 1` rather than freshly appending past the last
+	/// known block. Only the first item after the rewind carries it; later
+	/// pages of the re-scanned range have `None`.
+	pub reorged_from: Option<u64>,
 }
 
 pub enum LogStreamState<T: Transport> {
 	Wait,
-	FetchBlockNumber(CallResult<U256, T::Out>),
+	FetchBlockNumber(Timeout<ApiCall<U256, T::Out>>),
+	/// re-fetch `anchor_number` (the block our stored hash belongs to, not
+	/// necessarily `after`) to check whether a re-org replaced it.
+	CheckReorg {
+		last_confirmed_block: u64,
+		anchor_number: u64,
+		future: Timeout<ApiCall<Block<H256>, T::Out>>,
+	},
+	/// anchor checked out clean but `after` has since advanced past it: fetch
+	/// `after`'s hash so the next check has an up-to-date anchor.
+	RecordAnchor {
+		last_confirmed_block: u64,
+		future: Timeout<ApiCall<Block<H256>, T::Out>>,
+	},
 	FetchLogs {
 		from: u64,
 		to: u64,
-		future: CallResult<Vec<Log>, T::Out>,
+		last_confirmed_block: u64,
+		reorged_from: Option<u64>,
+		future: Timeout<ApiCall<Vec<Log>, T::Out>>,
+	},
+	NextItem {
+		item: Option<LogStreamItem>,
+		last_confirmed_block: u64,
 	},
-	NextItem(Option<LogStreamItem>),
+}
+
+/// maps a timed-out or inner RPC failure to the crate's `Error`, tagging
+/// timeouts with the method that stalled.
+fn map_timeout(method: &'static str, elapsed: Duration, err: TimeoutError<Error>) -> Error {
+	match err {
+		TimeoutError::Inner(e) => e,
+		TimeoutError::TimedOut => ErrorKind::RpcTimeout { method, elapsed }.into(),
+		TimeoutError::Timer(e) => ErrorKind::Timer(e).into(),
+	}
+}
+
+/// last block (inclusive) of the next page, given the page size (`0` = unbounded).
+fn page_to(from: u64, last_confirmed_block: u64, block_page_size: u64) -> u64 {
+	if block_page_size == 0 {
+		last_confirmed_block
+	} else {
+		::std::cmp::min(from + block_page_size - 1, last_confirmed_block)
+	}
+}
+
+/// `FetchLogs` for the newly confirmed range, or `Wait` if there's nothing new.
+fn fetch_logs_or_wait<T: Transport>(
+	transport: &T,
+	timer: &Timer,
+	filter: &FilterBuilder,
+	request_timeout: Duration,
+	block_page_size: u64,
+	after: u64,
+	last_confirmed_block: u64,
+	reorged_from: Option<u64>,
+) -> LogStreamState<T> {
+	if last_confirmed_block > after {
+		let from = after + 1;
+		let to = page_to(from, last_confirmed_block, block_page_size);
+		let built = filter.clone().from_block(from.into()).to_block(to.into()).build();
+		LogStreamState::FetchLogs {
+			from,
+			to,
+			last_confirmed_block,
+			reorged_from,
+			future: timer.timeout(logs(transport, &built), request_timeout),
+		}
+	} else {
+		LogStreamState::Wait
+	}
 }
 
 pub struct LogStream<T: Transport> {
 	transport: T,
+	timer: Timer,
 	interval: Interval,
 	state: LogStreamState<T>,
 	after: u64,
 	filter: FilterBuilder,
 	confirmations: usize,
+	block_page_size: u64,
+	reorg_rewind: u64,
+	request_timeout: Duration,
+	/// `(number, hash)` of the block our re-org check last verified.
+	last_confirmed: Option<(u64, H256)>,
 }
 
 impl<T: Transport> Stream for LogStream<T> {
@@ -69,40 +278,76 @@ impl<T: Transport> Stream for LogStream<T> {
 			let next_state = match self.state {
 				LogStreamState::Wait => {
 					let _ = try_stream!(self.interval.poll());
-					LogStreamState::FetchBlockNumber(block_number(&self.transport))
+					LogStreamState::FetchBlockNumber(self.timer.timeout(block_number(&self.transport), self.request_timeout))
 				},
 				LogStreamState::FetchBlockNumber(ref mut future) => {
-					let last_block = try_ready!(future.poll().map_err(ErrorKind::Web3)).low_u64();
+					let last_block = try_ready!(future.poll().map_err(|e| map_timeout("eth_blockNumber", self.request_timeout, e))).low_u64();
 					let last_confirmed_block = last_block.saturating_sub(self.confirmations as u64);
-					if last_confirmed_block > self.after {
-						let from = self.after + 1;
-						let filter = self.filter.clone()
-							.from_block(from.into())
-							.to_block(last_confirmed_block.into())
-							.build();
-						LogStreamState::FetchLogs {
-							from: from,
-							to: last_confirmed_block,
-							future: logs(&self.transport, &filter)
+					// re-verify the block our stored hash belongs to, not `after` itself:
+					// `after` may have moved past it since the last check via pagination.
+					let anchor_number = self.last_confirmed.map(|(number, _)| number).unwrap_or(self.after);
+					LogStreamState::CheckReorg {
+						last_confirmed_block,
+						anchor_number,
+						future: self.timer.timeout(block(&self.transport, BlockId::Number(anchor_number.into())), self.request_timeout),
+					}
+				},
+				LogStreamState::CheckReorg { ref mut future, last_confirmed_block, anchor_number } => {
+					let block = try_ready!(future.poll().map_err(|e| map_timeout("eth_getBlockByNumber", self.request_timeout, e)));
+					let reorged = match self.last_confirmed {
+						Some((number, hash)) => number == anchor_number && Some(hash) != block.hash,
+						None => false,
+					};
+
+					if reorged {
+						// a re-org deeper than `confirmations` happened: rewind from the point
+						// we know diverged and let the caller reconcile the re-scanned range.
+						self.after = anchor_number.saturating_sub(self.reorg_rewind);
+						self.last_confirmed = None;
+						let reorged_from = Some(self.after);
+						fetch_logs_or_wait(&self.transport, &self.timer, &self.filter, self.request_timeout, self.block_page_size, self.after, last_confirmed_block, reorged_from)
+					} else if anchor_number == self.after {
+						// anchor is already the current tip: record its hash and proceed.
+						if let Some(hash) = block.hash {
+							self.last_confirmed = Some((anchor_number, hash));
 						}
+						fetch_logs_or_wait(&self.transport, &self.timer, &self.filter, self.request_timeout, self.block_page_size, self.after, last_confirmed_block, None)
 					} else {
-						LogStreamState::Wait
+						// anchor checked out clean but `after` has since moved past it: fetch
+						// `after`'s hash before resuming so the next check has a fresh anchor.
+						LogStreamState::RecordAnchor {
+							last_confirmed_block,
+							future: self.timer.timeout(block(&self.transport, BlockId::Number(self.after.into())), self.request_timeout),
+						}
 					}
 				},
-				LogStreamState::FetchLogs { ref mut future, from, to } => {
-					let logs = try_ready!(future.poll().map_err(ErrorKind::Web3));
+				LogStreamState::RecordAnchor { ref mut future, last_confirmed_block } => {
+					let block = try_ready!(future.poll().map_err(|e| map_timeout("eth_getBlockByNumber", self.request_timeout, e)));
+					if let Some(hash) = block.hash {
+						self.last_confirmed = Some((self.after, hash));
+					}
+					fetch_logs_or_wait(&self.transport, &self.timer, &self.filter, self.request_timeout, self.block_page_size, self.after, last_confirmed_block, None)
+				},
+				LogStreamState::FetchLogs { ref mut future, from, to, last_confirmed_block, reorged_from } => {
+					let logs = try_ready!(future.poll().map_err(|e| map_timeout("eth_getLogs", self.request_timeout, e)));
 					let item = LogStreamItem {
 						from,
 						to,
 						logs,
+						reorged_from,
 					};
 
 					self.after = to;
-					LogStreamState::NextItem(Some(item))
+					LogStreamState::NextItem {
+						item: Some(item),
+						last_confirmed_block,
+					}
 				},
-				LogStreamState::NextItem(ref mut item) => match item.take() {
-					some => return Ok(some.into()),
-					None => LogStreamState::Wait,
+				LogStreamState::NextItem { ref mut item, last_confirmed_block } => match item.take() {
+					some @ Some(_) => return Ok(some.into()),
+					// more pages may be left in the already-known confirmed range: keep
+					// draining at full speed instead of going through `Wait` and re-polling.
+					None => fetch_logs_or_wait(&self.transport, &self.timer, &self.filter, self.request_timeout, self.block_page_size, self.after, last_confirmed_block, None),
 				},
 			};
 
@@ -112,12 +357,150 @@ impl<T: Transport> Stream for LogStream<T> {
 }
 
 pub fn log_stream<T: Transport>(transport: T, init: LogStreamInit) -> LogStream<T> {
+	let timer = Timer::default();
 	LogStream {
 		transport,
-		interval: Timer::default().interval(init.poll_interval),
+		interval: timer.interval(init.poll_interval),
+		timer,
 		state: LogStreamState::Wait,
 		after: init.after,
 		filter: init.filter,
 		confirmations: init.confirmations,
+		block_page_size: init.block_page_size,
+		reorg_rewind: init.reorg_rewind,
+		request_timeout: init.request_timeout,
+		last_confirmed: None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use web3::transports::test::TestTransport;
+	use web3::types::Address;
+	use serde_json::Value;
+
+	fn tx(from: Address) -> TransactionRequest {
+		TransactionRequest {
+			from,
+			to: None,
+			gas: None,
+			gas_price: None,
+			value: None,
+			data: None,
+			nonce: None,
+			condition: None,
+		}
+	}
+
+	fn hex_value<I: Into<U256>>(n: I) -> Value {
+		Value::String(format!("{:#x}", n.into()))
+	}
+
+	#[test]
+	fn send_with_nonce_primes_from_pending_on_first_use() {
+		let mut transport = TestTransport::default();
+		transport.set_response(hex_value(3));
+		transport.add_response(Value::String(format!("{:#x}", H256::zero())));
+
+		let mut nonce_manager = NonceManager::new();
+		let hash = send_transaction_with_nonce(transport.clone(), &mut nonce_manager, tx(Address::zero())).wait().unwrap();
+
+		assert_eq!(hash, H256::zero());
+		assert_eq!(nonce_manager.next(Address::zero()), Some(U256::from(4)));
+	}
+
+	#[test]
+	fn send_with_nonce_resyncs_after_failed_send() {
+		let mut transport = TestTransport::default();
+		transport.set_response(Value::String("not-a-hash".into()));
+		transport.add_response(hex_value(7));
+
+		let mut nonce_manager = NonceManager::new();
+		nonce_manager.resync(Address::zero(), U256::from(5));
+
+		let result = send_transaction_with_nonce(transport.clone(), &mut nonce_manager, tx(Address::zero())).wait();
+
+		assert!(result.is_err());
+		assert_eq!(nonce_manager.next(Address::zero()), Some(U256::from(7)));
+	}
+
+	#[test]
+	fn send_with_nonce_surfaces_send_error_even_if_resync_also_fails() {
+		let mut transport = TestTransport::default();
+		transport.set_response(Value::String("not-a-hash".into()));
+		transport.add_response(Value::String("also-not-a-hash".into()));
+
+		let mut nonce_manager = NonceManager::new();
+		nonce_manager.resync(Address::zero(), U256::from(1));
+
+		let result = send_transaction_with_nonce(transport.clone(), &mut nonce_manager, tx(Address::zero())).wait();
+
+		assert!(result.is_err());
+	}
+
+	fn block_json(number: u64, hash: H256) -> Value {
+		format!(
+			r#"{{
+				"number": "{:#x}",
+				"hash": "{:#x}",
+				"parentHash": "{:#x}",
+				"nonce": "0x0000000000000000",
+				"sha3Uncles": "{:#x}",
+				"logsBloom": "0x{}",
+				"transactionsRoot": "{:#x}",
+				"stateRoot": "{:#x}",
+				"receiptsRoot": "{:#x}",
+				"miner": "{:#x}",
+				"difficulty": "0x0",
+				"totalDifficulty": "0x0",
+				"extraData": "0x",
+				"size": "0x0",
+				"gasLimit": "0x0",
+				"gasUsed": "0x0",
+				"timestamp": "0x0",
+				"transactions": [],
+				"uncles": []
+			}}"#,
+			number, hash, H256::zero(), H256::zero(), "0".repeat(512), H256::zero(), H256::zero(), H256::zero(), Address::zero(),
+		).parse().unwrap()
+	}
+
+	fn test_stream(transport: TestTransport, after: u64, last_confirmed: Option<(u64, H256)>) -> LogStream<TestTransport> {
+		let timer = Timer::default();
+		LogStream {
+			state: LogStreamState::FetchBlockNumber(timer.timeout(block_number(transport.clone()), Duration::from_secs(5))),
+			interval: timer.interval(Duration::from_secs(3600)),
+			timer,
+			transport,
+			after,
+			filter: FilterBuilder::default(),
+			confirmations: 0,
+			block_page_size: 0,
+			reorg_rewind: 5,
+			request_timeout: Duration::from_secs(5),
+			last_confirmed,
+		}
+	}
+
+	#[test]
+	fn reorg_check_tracks_stored_anchor_not_the_advanced_after() {
+		let stale_anchor = 10;
+		let hash_a = H256::from_low_u64_be(0xa);
+		let hash_b = H256::from_low_u64_be(0xb);
+
+		let mut transport = TestTransport::default();
+		transport.set_response(hex_value(12));
+		transport.add_response(block_json(stale_anchor, hash_a));
+		transport.add_response(block_json(12, hash_b));
+
+		// `after` has already advanced past `stale_anchor` via a prior multi-page drain,
+		// while the stored anchor is still the old, lower block number.
+		let mut stream = test_stream(transport, 12, Some((stale_anchor, hash_a)));
+
+		let _ = stream.poll();
+
+		assert_eq!(stream.last_confirmed, Some((12, hash_b)));
+		assert_eq!(stream.after, 12);
 	}
 }
\ No newline at end of file